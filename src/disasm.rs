@@ -0,0 +1,76 @@
+//! Feature-gated disassembler: render a parsed [`OpbFile`](crate::OpbFile) as
+//! annotated, diff-friendly text.
+//!
+//! Each [`OpbCommand`](crate::OpbCommand) becomes one line carrying its
+//! accumulated `time`, the register address with its decoded OPL meaning, and
+//! the raw `data` byte. For Standard captures the `data_map` template index the
+//! write expanded from is appended as well. The whole module lives behind the
+//! `disasm` cargo feature so the formatting and `std` string machinery stay out
+//! of the default build.
+
+use std::fmt::Write;
+
+use crate::{OpbData, OpbFile, OpbFormat};
+
+// Decode the meaning of an OPL register address. The low byte selects the
+// register group; operator registers repeat per operator slot, the per-channel
+// registers (0xA0/0xB0/0xC0) key and tune a whole channel.
+fn decode_register(addr: u16) -> &'static str {
+	let reg = (addr & 0xff) as u8;
+	// The per-channel 0xA0/0xB0/0xC0 registers add only a 0..8 local, so they
+	// must be matched with a 0xf0 mask; a 0xe0 mask folds 0xB0 onto 0xA0 and
+	// mislabels every key-on write. The operator registers still need 0xe0
+	// because their operator offset can push e.g. 0x20 up to 0x35.
+	match reg & 0xf0 {
+		0xa0 => "F-number low",
+		0xb0 => "key-on/block/F-number high",
+		0xc0 => "feedback/connection",
+		_ => match reg & 0xe0 {
+			0x20 => "AM/VIB/EGT/KSR/mult",
+			0x40 => "KSL/total-level",
+			0x60 => "attack/decay",
+			0x80 => "sustain/release",
+			0xe0 => "wave-select",
+			_ => "unknown",
+		},
+	}
+}
+
+fn format_template(data: &OpbData) -> String {
+	let mut s = String::from("[");
+	for i in 0..(data.count as usize).min(16) {
+		if i > 0 {
+			s.push(' ');
+		}
+		let _ = write!(s, "{:02X}", data.args[i]);
+	}
+	s.push(']');
+	s
+}
+
+/// Disassemble `file` into a greppable, one-line-per-write dump of its register
+/// timeline. See the [module docs](self).
+pub fn disassemble(file: &OpbFile) -> String {
+	let mut out = String::new();
+	let is_standard = file.format() == OpbFormat::Standard;
+
+	for cmd in file.commands() {
+		let _ = write!(
+			out,
+			"{:>12.3}  {:#05X} {:<26} {:#04X}",
+			cmd.time,
+			cmd.addr,
+			decode_register(cmd.addr),
+			cmd.data,
+		);
+		if is_standard && cmd.data_index >= 0 {
+			let _ = write!(out, "  data[{}]", cmd.data_index);
+			if let Some(data) = file.data_map.get(cmd.data_index as usize) {
+				let _ = write!(out, " {}", format_template(data));
+			}
+		}
+		out.push('\n');
+	}
+
+	out
+}
@@ -1,5 +1,6 @@
+use std::collections::HashMap;
+
 use nom::{
-	branch::alt,
 	bytes::complete::tag,
 	Err::Failure,
 	error::{
@@ -8,22 +9,49 @@ use nom::{
 	},
 	IResult,
 	number::complete::{
+		be_i16,
+		be_u16,
 		be_u32,
 		u8
 	}
 };
 
+#[cfg(feature = "render")]
+mod render;
+#[cfg(feature = "render")]
+pub use render::{render, RenderOptions};
+
+#[cfg(feature = "disasm")]
+mod disasm;
+#[cfg(feature = "disasm")]
+pub use disasm::disassemble;
+
 const FILE_ID_SIZE: usize = 7;
 const FILE_ID: [u8; FILE_ID_SIZE] = [b'O', b'P', b'B', b'i', b'n', b'1', 0 ];
 const NUM_CHANNELS: usize = 18;
 const NUM_TRACKS: usize = NUM_CHANNELS + 1;
 
+// Per-channel modulator operator offsets within an OPL register bank; the
+// carrier operator sits three slots above its modulator. Channels 9..18 live
+// in the second (OPL3) bank and have 0x100 OR'd into every address.
+const OP_OFFSETS: [u16; 9] = [0, 1, 2, 8, 9, 10, 16, 17, 18];
+
 #[derive(Debug, PartialEq)]
 pub enum OpbError<'a> {
 	Format(u8),
 	Read(&'a [u8], ErrorKind),
 	NotAnOpbFile([u8; FILE_ID_SIZE]),
 	Version,
+	/// A field ran off the end of the input. Carries the human-readable field
+	/// name (e.g. `"instrument.feed_conn"`, `"chunk[3].time"`) and the byte
+	/// offset into the original input where the read started.
+	TruncatedField { field: &'static str, offset: usize },
+	/// A chunk referenced a table entry outside its declared bounds — e.g. an
+	/// instrument or `data_map` index past the end of the parsed table.
+	BadIndex { field: &'static str, index: usize, len: usize },
+	/// A capture used more distinct low-byte registers than the DRO v2 codemap
+	/// can address (127 entries; bit 7 of the index byte selects the OPL3 bank).
+	CodemapOverflow { count: usize },
 }
 
 impl<'a> ParseError<&'a [u8]> for OpbError<'a> {
@@ -36,6 +64,89 @@ impl<'a> ParseError<&'a [u8]> for OpbError<'a> {
 	}
 }
 
+impl<'a> std::fmt::Display for OpbError<'a> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			OpbError::Format(b) => write!(f, "unsupported format byte {b}"),
+			OpbError::Read(_, kind) => write!(f, "read error: {kind:?}"),
+			OpbError::NotAnOpbFile(id) => write!(f, "not an OPBin1 file: bad magic {id:02x?}"),
+			OpbError::Version => write!(f, "unsupported file version"),
+			OpbError::TruncatedField { field, offset } => {
+				write!(f, "not enough data reading {field} at offset {offset:#X}")
+			}
+			OpbError::BadIndex { field, index, len } => {
+				write!(f, "{field} index {index} out of bounds (table len {len})")
+			}
+			OpbError::CodemapOverflow { count } => {
+				write!(f, "{count} distinct registers exceed the DRO v2 codemap limit of 127")
+			}
+		}
+	}
+}
+
+impl<'a> std::error::Error for OpbError<'a> {}
+
+// A cursor over the original input that wraps the nom primitives used by the
+// parser so each failure records the field it was reading and the byte offset
+// it started at, instead of a bare `ErrorKind`. `origin` stays pinned to the
+// whole buffer so `offset` can be recovered from the slice length.
+struct Reader<'a> {
+	origin: &'a [u8],
+	input: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+	fn new(input: &'a [u8]) -> Self {
+		Reader { origin: input, input }
+	}
+
+	fn offset(&self) -> usize {
+		self.origin.len() - self.input.len()
+	}
+
+	fn tag(&mut self, bytes: &'static [u8], field: &'static str) -> Result<&'a [u8], OpbError<'a>> {
+		match tag::<_, _, OpbError<'a>>(bytes)(self.input) {
+			Ok((rest, v)) => { self.input = rest; Ok(v) }
+			Err(_) => Err(OpbError::TruncatedField { field, offset: self.offset() }),
+		}
+	}
+
+	fn u8(&mut self, field: &'static str) -> Result<u8, OpbError<'a>> {
+		match u8::<_, OpbError<'a>>(self.input) {
+			Ok((rest, v)) => { self.input = rest; Ok(v) }
+			Err(_) => Err(OpbError::TruncatedField { field, offset: self.offset() }),
+		}
+	}
+
+	fn be_u16(&mut self, field: &'static str) -> Result<u16, OpbError<'a>> {
+		match be_u16::<_, OpbError<'a>>(self.input) {
+			Ok((rest, v)) => { self.input = rest; Ok(v) }
+			Err(_) => Err(OpbError::TruncatedField { field, offset: self.offset() }),
+		}
+	}
+
+	fn be_u32(&mut self, field: &'static str) -> Result<u32, OpbError<'a>> {
+		match be_u32::<_, OpbError<'a>>(self.input) {
+			Ok((rest, v)) => { self.input = rest; Ok(v) }
+			Err(_) => Err(OpbError::TruncatedField { field, offset: self.offset() }),
+		}
+	}
+
+	fn be_i16(&mut self, field: &'static str) -> Result<i16, OpbError<'a>> {
+		match be_i16::<_, OpbError<'a>>(self.input) {
+			Ok((rest, v)) => { self.input = rest; Ok(v) }
+			Err(_) => Err(OpbError::TruncatedField { field, offset: self.offset() }),
+		}
+	}
+
+	fn read_u7(&mut self, field: &'static str) -> Result<u32, OpbError<'a>> {
+		match read_u7(self.input) {
+			Ok((rest, v)) => { self.input = rest; Ok(v) }
+			Err(_) => Err(OpbError::TruncatedField { field, offset: self.offset() }),
+		}
+	}
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum OpbFormat {
@@ -55,15 +166,18 @@ struct OpbHeader {
 #[derive(Clone, Debug, PartialEq)]
 pub struct OpbFile {
 	header: OpbHeader,
+	cmd_stream: Vec<OpbCommand>,
+	instruments: Vec<OpbInstrument>,
+	data_map: Vec<OpbData>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
-struct OpbCommand {
-	addr: u16,
-	data: u8,
-	time: f64,
-	order_index: i32,
-	data_index: i32,
+pub struct OpbCommand {
+	pub addr: u16,
+	pub data: u8,
+	pub time: f64,
+	pub order_index: i32,
+	pub data_index: i32,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -91,12 +205,8 @@ struct OpbData {
 #[derive(Clone, Debug, PartialEq)]
 struct OpbContext {
 	cmd_stream: Vec<OpbCommand>,
-	fmt: OpbFormat,
 	data_map: Vec<OpbData>,
 	instruments: Vec<OpbInstrument>,
-	tracks: [Vec<OpbCommand>; NUM_TRACKS],
-	time: f64,
-
 }
 
 fn read_u7<'a>(input: &'a [u8]) -> IResult<&'a [u8], u32, OpbError<'a>> {
@@ -126,7 +236,7 @@ fn read_u7<'a>(input: &'a [u8]) -> IResult<&'a [u8], u32, OpbError<'a>> {
 		}
 	}
 
-	Ok((input, (b0 | (b1 << 7) | (b2 << 14) | (b3 << 21)) as u32))
+	Ok((input, (b0 as u32) | ((b1 as u32) << 7) | ((b2 as u32) << 14) | ((b3 as u32) << 21)))
 }
 
 const fn size_u7(val: u32) -> usize {
@@ -138,16 +248,565 @@ const fn size_u7(val: u32) -> usize {
 	}
 }
 
-pub fn parse_opb<'a>(input: &'a [u8]) -> IResult<&'a [u8], OpbFile, OpbError<'a>> {
-	let (input, id) = tag("OPBin1\x00")(input)?;
+// Inverse of `read_u7`: emit `val` seven bits at a time, low group first, with
+// the 0x80 continuation bit set on every byte except the last. Capped at four
+// bytes (28 bits) to match the reader.
+fn write_u7(out: &mut Vec<u8>, val: u32) {
+	out.reserve(size_u7(val));
+	let mut remaining = val & 0x0fff_ffff;
+	for i in 0..4 {
+		let mut byte = (remaining & 0x7f) as u8;
+		remaining >>= 7;
+		if remaining == 0 || i == 3 {
+			out.push(byte);
+			break;
+		}
+		byte |= 0x80;
+		out.push(byte);
+	}
+}
+
+// Resolve a channel index (0..NUM_CHANNELS) into the OPL register offsets it
+// writes to: the bank bit, the channel-local offset used by 0xA0/0xB0/0xC0,
+// and the modulator/carrier operator offsets used by 0x20/0x40/0x60/0x80/0xE0.
+fn channel_regs(channel: usize) -> (u16, u16, u16, u16) {
+	let bank = if channel >= 9 { 0x100 } else { 0 };
+	let local = (channel % 9) as u16;
+	let modop = OP_OFFSETS[channel % 9];
+	(bank, local, modop, modop + 3)
+}
+
+// Expand a Standard-format tone event into the register-write timeline it
+// stands for. The instrument supplies the static operator registers (0x20
+// characteristic, 0x60 attack/decay, 0x80 sustain/release, 0xE0 wave-select
+// for both operators, plus 0xC0 feedback/connection); the four data bytes
+// supply the per-note operator levels (0x40) and the F-number/block/key-on
+// pair (0xA0/0xB0).
+fn expand_tone(channel: usize, inst: &OpbInstrument, data: &OpbData) -> Vec<(u16, u8)> {
+	let (bank, local, modop, carop) = channel_regs(channel);
+	vec![
+		(bank | (0x20 + modop), inst.modulator.characteristic as u8),
+		(bank | (0x20 + carop), inst.carrier.characteristic as u8),
+		(bank | (0x40 + modop), data.args[0]),
+		(bank | (0x40 + carop), data.args[1]),
+		(bank | (0x60 + modop), inst.modulator.attack_decay as u8),
+		(bank | (0x60 + carop), inst.carrier.attack_decay as u8),
+		(bank | (0x80 + modop), inst.modulator.sustain_release as u8),
+		(bank | (0x80 + carop), inst.carrier.sustain_release as u8),
+		(bank | (0xE0 + modop), inst.modulator.wave_select as u8),
+		(bank | (0xE0 + carop), inst.carrier.wave_select as u8),
+		(bank | (0xC0 + local), inst.feed_conn as u8),
+		(bank | (0xA0 + local), data.args[2]),
+		(bank | (0xB0 + local), data.args[3]),
+	]
+}
+
+fn read_instrument<'a>(r: &mut Reader<'a>, index: i32) -> Result<OpbInstrument, OpbError<'a>> {
+	let feed_conn = r.be_i16("instrument.feed_conn")?;
+	let mc = r.be_i16("instrument.modulator.characteristic")?;
+	let mad = r.be_i16("instrument.modulator.attack_decay")?;
+	let msr = r.be_i16("instrument.modulator.sustain_release")?;
+	let mws = r.be_i16("instrument.modulator.wave_select")?;
+	let cc = r.be_i16("instrument.carrier.characteristic")?;
+	let cad = r.be_i16("instrument.carrier.attack_decay")?;
+	let csr = r.be_i16("instrument.carrier.sustain_release")?;
+	let cws = r.be_i16("instrument.carrier.wave_select")?;
+
+	Ok(OpbInstrument {
+		feed_conn,
+		modulator: OpbInstOp { characteristic: mc, attack_decay: mad, sustain_release: msr, wave_select: mws },
+		carrier: OpbInstOp { characteristic: cc, attack_decay: cad, sustain_release: csr, wave_select: cws },
+		index,
+	})
+}
+
+fn read_data<'a>(r: &mut Reader<'a>) -> Result<OpbData, OpbError<'a>> {
+	let count = r.read_u7("data.count")?;
+	let mut args = [0u8; 16];
+	for slot in args.iter_mut().take((count as usize).min(16)) {
+		*slot = r.u8("data.args")?;
+	}
+	Ok(OpbData { count, args })
+}
+
+// Raw bodies are a flat run of (u7 time-delta, u16 address, u8 data) triples;
+// the running time is the accumulation of the deltas.
+fn decode_raw<'a>(r: &mut Reader<'a>, num_chunks: u32) -> Result<OpbContext, OpbError<'a>> {
+	let mut cmd_stream = Vec::with_capacity(num_chunks as usize);
+	let mut time = 0.0f64;
+	for order in 0..num_chunks as i32 {
+		let elapsed = r.read_u7("command.time")?;
+		let addr = r.be_u16("command.addr")?;
+		let data = r.u8("command.data")?;
+		time += elapsed as f64;
+		cmd_stream.push(OpbCommand { addr, data, time, order_index: order, data_index: -1 });
+	}
+
+	Ok(OpbContext {
+		cmd_stream,
+		data_map: Vec::new(),
+		instruments: Vec::new(),
+	})
+}
+
+// Standard bodies are the instrument table, the interned `data_map` of per-note
+// argument runs, then `num_chunks` time-grouped chunks. Each chunk carries a
+// u7 time delta, a u7 count of tone events (instrument index + channel + data
+// template) and a u7 count of loose register writes; tone events expand against
+// the instrument table and land in their channel `tracks`, loose writes land in
+// the global track. The expanded timeline is returned sorted by time then file
+// order.
+fn decode_standard<'a>(r: &mut Reader<'a>, header: &OpbHeader) -> Result<OpbContext, OpbError<'a>> {
+	let mut instruments = Vec::with_capacity(header.num_instruments as usize);
+	for index in 0..header.num_instruments as i32 {
+		let inst = read_instrument(r, index)?;
+		instruments.push(inst);
+	}
+
+	let data_len = r.read_u7("data_map.len")?;
+	let mut data_map = Vec::with_capacity(data_len as usize);
+	for _ in 0..data_len {
+		let data = read_data(r)?;
+		data_map.push(data);
+	}
+
+	let mut tracks: [Vec<OpbCommand>; NUM_TRACKS] = Default::default();
+	let mut time = 0.0f64;
+	let mut order = 0i32;
+
+	for _ in 0..header.num_chunks {
+		let elapsed = r.read_u7("chunk.time")?;
+		let tone_count = r.read_u7("chunk.tone_count")?;
+		let loose_count = r.read_u7("chunk.loose_count")?;
+		time += elapsed as f64;
+
+		for _ in 0..tone_count {
+			let inst_ix = r.read_u7("chunk.tone.instrument")?;
+			let channel = r.read_u7("chunk.tone.channel")?;
+			let data_ix = r.read_u7("chunk.tone.data")?;
+
+			let inst = *instruments.get(inst_ix as usize).ok_or(OpbError::BadIndex {
+				field: "chunk.tone.instrument",
+				index: inst_ix as usize,
+				len: instruments.len(),
+			})?;
+			let data = *data_map.get(data_ix as usize).ok_or(OpbError::BadIndex {
+				field: "chunk.tone.data",
+				index: data_ix as usize,
+				len: data_map.len(),
+			})?;
+			let channel = channel as usize;
+			if channel >= NUM_CHANNELS {
+				return Err(OpbError::BadIndex {
+					field: "chunk.tone.channel",
+					index: channel,
+					len: NUM_CHANNELS,
+				});
+			}
+			for (addr, byte) in expand_tone(channel, &inst, &data) {
+				tracks[channel].push(OpbCommand {
+					addr,
+					data: byte,
+					time,
+					order_index: order,
+					data_index: data_ix as i32,
+				});
+				order += 1;
+			}
+		}
+
+		for _ in 0..loose_count {
+			let addr = r.be_u16("chunk.loose.addr")?;
+			let data = r.u8("chunk.loose.data")?;
+			tracks[NUM_CHANNELS].push(OpbCommand { addr, data, time, order_index: order, data_index: -1 });
+			order += 1;
+		}
+	}
+
+	let mut cmd_stream: Vec<OpbCommand> = tracks.iter().flatten().copied().collect();
+	cmd_stream.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap().then(a.order_index.cmp(&b.order_index)));
+
+	Ok(OpbContext {
+		cmd_stream,
+		data_map,
+		instruments,
+	})
+}
+
+// A time-grouped Standard chunk, mirror image of what `decode_standard` reads.
+struct StdChunk {
+	elapsed: u32,
+	tones: Vec<(u32, u32, u32)>,
+	loose: Vec<(u16, u8)>,
+}
+
+fn intern_instrument(list: &mut Vec<OpbInstrument>, mut inst: OpbInstrument) -> u32 {
+	for existing in list.iter() {
+		if existing.feed_conn == inst.feed_conn
+			&& existing.modulator == inst.modulator
+			&& existing.carrier == inst.carrier
+		{
+			return existing.index as u32;
+		}
+	}
+	let index = list.len() as u32;
+	inst.index = index as i32;
+	list.push(inst);
+	index
+}
+
+fn intern_data(list: &mut Vec<OpbData>, data: OpbData) -> u32 {
+	if let Some(pos) = list.iter().position(|d| *d == data) {
+		return pos as u32;
+	}
+	list.push(data);
+	(list.len() - 1) as u32
+}
+
+// Re-derive the Standard-format structure from a decoded command stream: detect
+// per-channel note events (a full canonical register set at one timestamp),
+// hoist their static operator registers into a deduplicated instrument table,
+// intern their per-note argument bytes into the `data_map`, and leave any
+// writes that are not part of a note as loose register writes. This is the
+// inverse of `expand_tone`/`decode_standard` and the workhorse behind both
+// `write_opb` and `OpbFile::to_format`.
+fn build_standard(cmd_stream: &[OpbCommand]) -> (Vec<OpbInstrument>, Vec<OpbData>, Vec<StdChunk>) {
+	let mut commands = cmd_stream.to_vec();
+	commands.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap().then(a.order_index.cmp(&b.order_index)));
+
+	let mut instruments = Vec::new();
+	let mut data_map = Vec::new();
+	let mut chunks = Vec::new();
+	let mut prev_time = 0.0f64;
+
+	let mut i = 0;
+	while i < commands.len() {
+		let time = commands[i].time;
+		let mut j = i;
+		while j < commands.len() && commands[j].time == time {
+			j += 1;
+		}
+
+		let entries: Vec<(u16, u8)> = commands[i..j].iter().map(|c| (c.addr, c.data)).collect();
+		let mut consumed = vec![false; entries.len()];
+		let mut first: HashMap<u16, usize> = HashMap::new();
+		for (k, (addr, _)) in entries.iter().enumerate() {
+			first.entry(*addr).or_insert(k);
+		}
+
+		let mut tones = Vec::new();
+		for ch in 0..NUM_CHANNELS {
+			let (bank, local, modop, carop) = channel_regs(ch);
+			let addrs = [
+				bank | (0x20 + modop), bank | (0x20 + carop),
+				bank | (0x40 + modop), bank | (0x40 + carop),
+				bank | (0x60 + modop), bank | (0x60 + carop),
+				bank | (0x80 + modop), bank | (0x80 + carop),
+				bank | (0xE0 + modop), bank | (0xE0 + carop),
+				bank | (0xC0 + local),
+				bank | (0xA0 + local), bank | (0xB0 + local),
+			];
+
+			let mut slots = [0usize; 13];
+			let mut complete = true;
+			for (n, addr) in addrs.iter().enumerate() {
+				match first.get(addr) {
+					Some(&k) if !consumed[k] => slots[n] = k,
+					_ => { complete = false; break; }
+				}
+			}
+			if !complete {
+				continue;
+			}
+
+			let val = |n: usize| entries[slots[n]].1;
+			let inst = OpbInstrument {
+				feed_conn: val(10) as i16,
+				modulator: OpbInstOp {
+					characteristic: val(0) as i16,
+					attack_decay: val(4) as i16,
+					sustain_release: val(6) as i16,
+					wave_select: val(8) as i16,
+				},
+				carrier: OpbInstOp {
+					characteristic: val(1) as i16,
+					attack_decay: val(5) as i16,
+					sustain_release: val(7) as i16,
+					wave_select: val(9) as i16,
+				},
+				index: 0,
+			};
+			let mut args = [0u8; 16];
+			args[0] = val(2);
+			args[1] = val(3);
+			args[2] = val(11);
+			args[3] = val(12);
+			let data = OpbData { count: 4, args };
+
+			for &k in &slots {
+				consumed[k] = true;
+			}
+			let inst_ix = intern_instrument(&mut instruments, inst);
+			let data_ix = intern_data(&mut data_map, data);
+			tones.push((inst_ix, ch as u32, data_ix));
+		}
+
+		let loose: Vec<(u16, u8)> = entries
+			.iter()
+			.enumerate()
+			.filter(|(k, _)| !consumed[*k])
+			.map(|(_, e)| *e)
+			.collect();
+
+		chunks.push(StdChunk {
+			elapsed: (time - prev_time).round() as u32,
+			tones,
+			loose,
+		});
+		prev_time = time;
+		i = j;
+	}
+
+	(instruments, data_map, chunks)
+}
+
+fn encode_standard(cmd_stream: &[OpbCommand]) -> (Vec<u8>, u32, u32) {
+	let (instruments, data_map, chunks) = build_standard(cmd_stream);
+	let mut body = Vec::new();
+
+	for inst in &instruments {
+		for field in [
+			inst.feed_conn,
+			inst.modulator.characteristic,
+			inst.modulator.attack_decay,
+			inst.modulator.sustain_release,
+			inst.modulator.wave_select,
+			inst.carrier.characteristic,
+			inst.carrier.attack_decay,
+			inst.carrier.sustain_release,
+			inst.carrier.wave_select,
+		] {
+			body.extend_from_slice(&field.to_be_bytes());
+		}
+	}
+
+	write_u7(&mut body, data_map.len() as u32);
+	for data in &data_map {
+		write_u7(&mut body, data.count);
+		for i in 0..(data.count as usize).min(16) {
+			body.push(data.args[i]);
+		}
+	}
+
+	for chunk in &chunks {
+		write_u7(&mut body, chunk.elapsed);
+		write_u7(&mut body, chunk.tones.len() as u32);
+		write_u7(&mut body, chunk.loose.len() as u32);
+		for &(inst_ix, channel, data_ix) in &chunk.tones {
+			write_u7(&mut body, inst_ix);
+			write_u7(&mut body, channel);
+			write_u7(&mut body, data_ix);
+		}
+		for &(addr, data) in &chunk.loose {
+			body.extend_from_slice(&addr.to_be_bytes());
+			body.push(data);
+		}
+	}
+
+	(body, instruments.len() as u32, chunks.len() as u32)
+}
+
+fn encode_raw(cmd_stream: &[OpbCommand]) -> (Vec<u8>, u32, u32) {
+	let mut commands = cmd_stream.to_vec();
+	commands.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap().then(a.order_index.cmp(&b.order_index)));
+
+	let mut body = Vec::new();
+	let mut prev_time = 0.0f64;
+	for cmd in &commands {
+		write_u7(&mut body, (cmd.time - prev_time).round() as u32);
+		body.extend_from_slice(&cmd.addr.to_be_bytes());
+		body.push(cmd.data);
+		prev_time = cmd.time;
+	}
+
+	(body, 0, commands.len() as u32)
+}
+
+/// Serialize an `OpbFile` back into an `OPBin1` byte buffer, the inverse of
+/// [`parse_opb`]. Raw files flatten to time-sorted triples; Standard files are
+/// re-chunked against a freshly deduplicated instrument table and `data_map`.
+pub fn write_opb(file: &OpbFile) -> Vec<u8> {
+	let (body, num_instruments, num_chunks) = match file.header.fmt {
+		OpbFormat::Raw => encode_raw(&file.cmd_stream),
+		OpbFormat::Standard => encode_standard(&file.cmd_stream),
+	};
+
+	let mut out = Vec::with_capacity(FILE_ID_SIZE + 13 + body.len());
+	out.extend_from_slice(&FILE_ID);
+	out.push(file.header.fmt as u8);
+	out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+	out.extend_from_slice(&num_instruments.to_be_bytes());
+	out.extend_from_slice(&num_chunks.to_be_bytes());
+	out.extend_from_slice(&body);
+	out
+}
+
+// Both DRO and VGM are time-ordered register-write logs, so an exporter only
+// has to walk the decoded command stream once, emitting a chip write per
+// `OpbCommand` and a wait derived from the gap to the next command's `time`.
+// OPB deltas are whole milliseconds (the unit `read_u7` accumulates into
+// `OpbCommand::time`); DRO stores those directly, VGM scales them to its
+// fixed 44100 Hz sample clock.
+const VGM_SAMPLE_RATE: f64 = 44100.0;
+
+// DOSBox DRO v2 header signature and the three delay/codemap control bytes we
+// pick. The short-delay code carries a 1..256 ms wait, the long-delay code a
+// (1..256) * 256 ms wait; everything else indexes the codemap.
+const DRO_SIGNATURE: &[u8; 8] = b"DBRAWOPL";
+const DRO_SHORT_DELAY: u8 = 0x00;
+const DRO_LONG_DELAY: u8 = 0x01;
+
+// DRO v2 addresses the two OPL3 register banks through a codemap of the
+// distinct low-byte register numbers, with bit 7 of the index byte selecting
+// the high (0x100) bank. Build that codemap from the writes actually present.
+fn dro_codemap(cmd_stream: &[OpbCommand]) -> Vec<u8> {
+	let mut map = Vec::new();
+	for cmd in cmd_stream {
+		let reg = (cmd.addr & 0xff) as u8;
+		if !map.contains(&reg) {
+			map.push(reg);
+		}
+	}
+	map
+}
+
+fn emit_dro_delay(out: &mut Vec<u8>, mut ms: u32) {
+	while ms > 0 {
+		if ms > 256 {
+			let units = (ms / 256).min(256);
+			out.push(DRO_LONG_DELAY);
+			out.push((units - 1) as u8);
+			ms -= units * 256;
+		} else {
+			out.push(DRO_SHORT_DELAY);
+			out.push((ms - 1) as u8);
+			ms = 0;
+		}
+	}
+}
+
+/// Export the decoded register-write timeline as a DOSBox DRO v2 capture.
+///
+/// Every `OpbCommand` becomes a register/value pair (the register indexes into
+/// a codemap, with bit 7 selecting the high OPL3 bank); the millisecond gap to
+/// the following command is emitted as short/long delay codes. Fails with
+/// [`OpbError::CodemapOverflow`] if the capture touches more than 127 distinct
+/// low-byte registers, which no longer fit the 7-bit codemap index.
+pub fn write_dro(file: &OpbFile) -> Result<Vec<u8>, OpbError<'static>> {
+	let mut commands = file.cmd_stream.clone();
+	commands.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap().then(a.order_index.cmp(&b.order_index)));
+
+	let codemap = dro_codemap(&commands);
+	if codemap.len() > 127 {
+		return Err(OpbError::CodemapOverflow { count: codemap.len() });
+	}
+	let mut data = Vec::new();
+	let mut prev_time = 0.0f64;
+	let length_ms = commands.last().map(|c| c.time).unwrap_or(0.0);
+
+	for cmd in &commands {
+		let delay = (cmd.time - prev_time).round() as i64;
+		if delay > 0 {
+			emit_dro_delay(&mut data, delay as u32);
+		}
+		prev_time = cmd.time;
+
+		let reg = (cmd.addr & 0xff) as u8;
+		let mut index = codemap.iter().position(|&r| r == reg).unwrap() as u8;
+		if cmd.addr & 0x100 != 0 {
+			index |= 0x80;
+		}
+		data.push(index);
+		data.push(cmd.data);
+	}
+
+	let mut out = Vec::with_capacity(26 + codemap.len() + data.len());
+	out.extend_from_slice(DRO_SIGNATURE);
+	out.extend_from_slice(&2u16.to_le_bytes()); // version major
+	out.extend_from_slice(&0u16.to_le_bytes()); // version minor
+	out.extend_from_slice(&((data.len() / 2) as u32).to_le_bytes()); // total register/value + delay pairs
+	out.extend_from_slice(&(length_ms.round() as u32).to_le_bytes()); // length in ms
+	out.push(2); // hardware type: OPL3
+	out.push(0); // format: interleaved register/value
+	out.push(0); // compression: none
+	out.push(DRO_SHORT_DELAY);
+	out.push(DRO_LONG_DELAY);
+	out.push(codemap.len() as u8);
+	out.extend_from_slice(&codemap);
+	out.extend_from_slice(&data);
+	Ok(out)
+}
+
+fn emit_vgm_wait(out: &mut Vec<u8>, mut samples: u32) {
+	while samples > 0 {
+		let step = samples.min(0xffff);
+		out.push(0x61);
+		out.extend_from_slice(&(step as u16).to_le_bytes());
+		samples -= step;
+	}
+}
+
+/// Export the decoded register-write timeline as a VGM log for the YMF262
+/// (OPL3). Low-bank writes use the 0x5E opcode, high-bank (0x100) writes 0x5F,
+/// and the millisecond gap between commands is emitted as 0x61 waits at the
+/// VGM 44100 Hz sample clock, closed by the 0x66 end marker.
+pub fn write_vgm(file: &OpbFile) -> Vec<u8> {
+	let mut commands = file.cmd_stream.clone();
+	commands.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap().then(a.order_index.cmp(&b.order_index)));
+
+	let mut data = Vec::new();
+	let mut prev_time = 0.0f64;
+	let mut total_samples = 0u32;
+
+	for cmd in &commands {
+		let delay_ms = (cmd.time - prev_time).max(0.0);
+		let samples = (delay_ms * VGM_SAMPLE_RATE / 1000.0).round() as u32;
+		if samples > 0 {
+			emit_vgm_wait(&mut data, samples);
+			total_samples += samples;
+		}
+		prev_time = cmd.time;
+
+		data.push(if cmd.addr & 0x100 != 0 { 0x5f } else { 0x5e });
+		data.push((cmd.addr & 0xff) as u8);
+		data.push(cmd.data);
+	}
+	data.push(0x66); // end of sound data
+
+	let header_size = 0x100usize;
+	let mut out = vec![0u8; header_size];
+	out[0x00..0x04].copy_from_slice(b"Vgm ");
+	out[0x08..0x0c].copy_from_slice(&0x0000_0151u32.to_le_bytes()); // version 1.51
+	out[0x18..0x1c].copy_from_slice(&total_samples.to_le_bytes()); // total # samples
+	out[0x34..0x38].copy_from_slice(&((header_size - 0x34) as u32).to_le_bytes()); // data offset
+	out[0x5c..0x60].copy_from_slice(&14_318_180u32.to_le_bytes()); // YMF262 clock
+	out.extend_from_slice(&data);
+
+	let eof = (out.len() - 0x04) as u32;
+	out[0x04..0x08].copy_from_slice(&eof.to_le_bytes()); // EOF offset
+	out
+}
+
+fn parse_header<'a>(r: &mut Reader<'a>) -> Result<OpbHeader, OpbError<'a>> {
+	let id = r.tag(b"OPBin1\x00", "header.id")?;
 	let id: [u8; FILE_ID_SIZE] = [id[0], id[1], id[2], id[3], id[4], id[5], id[6]];
 	if id != FILE_ID {
-		return Err(Failure(OpbError::NotAnOpbFile(id)));
+		return Err(OpbError::NotAnOpbFile(id));
 	}
 
-	let (input, fmti) = u8(input)?;
+	let fmti = r.u8("header.format")?;
 	if fmti > 1 {
-		return Err(Failure(OpbError::Format(fmti)));
+		return Err(OpbError::Format(fmti));
 	}
 	let fmt = match fmti {
 		0 => OpbFormat::Standard,
@@ -155,26 +814,263 @@ pub fn parse_opb<'a>(input: &'a [u8]) -> IResult<&'a [u8], OpbFile, OpbError<'a>
 		_ => unreachable!(),
 	};
 
-	let (input, size) = be_u32(input)?;
-	let (input, ninst) = be_u32(input)?;
-	let (input, nchunks) = be_u32(input)?;
+	let size = r.be_u32("header.size")?;
+	let ninst = r.be_u32("header.num_instruments")?;
+	let nchunks = r.be_u32("header.num_chunks")?;
+
+	Ok(OpbHeader {
+		id,
+		fmt,
+		size,
+		num_instruments: ninst,
+		num_chunks: nchunks,
+	})
+}
+
+pub fn parse_opb<'a>(input: &'a [u8]) -> IResult<&'a [u8], OpbFile, OpbError<'a>> {
+	let mut r = Reader::new(input);
+
+	let result = (|| {
+		let header = parse_header(&mut r)?;
+		let ctx = match header.fmt {
+			OpbFormat::Raw => decode_raw(&mut r, header.num_chunks)?,
+			OpbFormat::Standard => decode_standard(&mut r, &header)?,
+		};
+		Ok(OpbFile {
+			header,
+			cmd_stream: ctx.cmd_stream,
+			instruments: ctx.instruments,
+			data_map: ctx.data_map,
+		})
+	})();
 
-	Ok((input, OpbFile {
-		header: OpbHeader {
-			id: id,
-			fmt: fmt,
-			size: size,
-			num_instruments: ninst,
-			num_chunks: nchunks,
-		},
-	}))
+	match result {
+		Ok(file) => Ok((r.input, file)),
+		Err(e) => Err(Failure(e)),
+	}
+}
+
+impl OpbFile {
+	/// Format this file was parsed from (or will serialize back to).
+	pub fn format(&self) -> OpbFormat {
+		self.header.fmt
+	}
+
+	/// The fully expanded register-write timeline, sorted by time then file
+	/// order. This is the decoded contents of both Raw and Standard captures.
+	pub fn commands(&self) -> &[OpbCommand] {
+		&self.cmd_stream
+	}
+
+	/// Serialize this file back to an `OPBin1` byte buffer. See [`write_opb`].
+	pub fn to_bytes(&self) -> Vec<u8> {
+		write_opb(self)
+	}
+
+	/// Export the decoded timeline as a DOSBox DRO v2 capture. See [`write_dro`].
+	pub fn to_dro(&self) -> Result<Vec<u8>, OpbError<'static>> {
+		write_dro(self)
+	}
+
+	/// Export the decoded timeline as a YMF262 VGM log. See [`write_vgm`].
+	pub fn to_vgm(&self) -> Vec<u8> {
+		write_vgm(self)
+	}
+
+	/// Render this file to interleaved stereo PCM via the OPL emulator. Gated
+	/// behind the `render` feature. See [`render`].
+	#[cfg(feature = "render")]
+	pub fn render(&self, opts: RenderOptions) -> Vec<i16> {
+		render::render(self, opts)
+	}
+
+	/// Disassemble this file into an annotated text dump of its register
+	/// timeline. Gated behind the `disasm` feature. See [`disassemble`].
+	#[cfg(feature = "disasm")]
+	pub fn disassemble(&self) -> String {
+		disasm::disassemble(self)
+	}
+
+	/// Re-encode this file into the other [`OpbFormat`], returning a new file.
+	///
+	/// Raw→Standard does the interesting work: the decoded command stream is
+	/// scanned for recurring note events, their OPL operator register sets are
+	/// hoisted into a deduplicated instrument table and their per-note argument
+	/// runs interned into the `data_map` (see [`build_standard`]).
+	/// Standard→Raw flattens back to the time-sorted triples. The decoded
+	/// command stream is format-independent, so conversion is lossless.
+	pub fn to_format(&self, fmt: OpbFormat) -> OpbFile {
+		if fmt == self.header.fmt {
+			return self.clone();
+		}
+
+		let mut header = self.header;
+		header.fmt = fmt;
+
+		let (instruments, data_map) = match fmt {
+			OpbFormat::Standard => {
+				let (instruments, data_map, chunks) = build_standard(&self.cmd_stream);
+				header.num_instruments = instruments.len() as u32;
+				header.num_chunks = chunks.len() as u32;
+				(instruments, data_map)
+			}
+			OpbFormat::Raw => {
+				header.num_instruments = 0;
+				header.num_chunks = self.cmd_stream.len() as u32;
+				(Vec::new(), Vec::new())
+			}
+		};
+
+		OpbFile {
+			header,
+			cmd_stream: self.cmd_stream.clone(),
+			instruments,
+			data_map,
+		}
+	}
 }
 
 #[cfg(test)]
 mod tests {
+	use super::{OpbCommand, OpbData, OpbFile, OpbFormat, OpbHeader, OpbInstOp, OpbInstrument};
+
+	// Build a Raw `OpbFile` from a decoded command stream, so tests exercise
+	// the encoder/converter without an external capture fixture. Its body
+	// carries one full canonical tone on channel 0 (so Raw→Standard has an
+	// instrument and a data template to extract) plus a couple of loose writes.
+	fn sample_file() -> OpbFile {
+		let inst = OpbInstrument {
+			feed_conn: 0x0a,
+			modulator: OpbInstOp { characteristic: 0x21, attack_decay: 0xf0, sustain_release: 0x77, wave_select: 0x01 },
+			carrier: OpbInstOp { characteristic: 0x20, attack_decay: 0xf0, sustain_release: 0x88, wave_select: 0x00 },
+			index: 0,
+		};
+		let data = OpbData { count: 4, args: [0x1f, 0x00, 0x45, 0x31, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] };
+
+		let mut cmd_stream = Vec::new();
+		let mut order = 0i32;
+		for (addr, byte) in super::expand_tone(0, &inst, &data) {
+			cmd_stream.push(OpbCommand { addr, data: byte, time: 0.0, order_index: order, data_index: -1 });
+			order += 1;
+		}
+		// A couple of loose writes at a later timestamp.
+		cmd_stream.push(OpbCommand { addr: 0x01, data: 0x20, time: 10.0, order_index: order, data_index: -1 });
+		order += 1;
+		cmd_stream.push(OpbCommand { addr: 0xbd, data: 0xc0, time: 10.0, order_index: order, data_index: -1 });
+
+		OpbFile {
+			header: OpbHeader {
+				id: super::FILE_ID,
+				fmt: OpbFormat::Raw,
+				size: 0,
+				num_instruments: 0,
+				num_chunks: cmd_stream.len() as u32,
+			},
+			cmd_stream,
+			instruments: Vec::new(),
+			data_map: Vec::new(),
+		}
+	}
+
+	// Project the decoded timeline down to the fields that must survive a
+	// format conversion, order-independent.
+	fn stream(file: &OpbFile) -> Vec<(u16, u8, i64)> {
+		let mut v: Vec<(u16, u8, i64)> = file
+			.commands()
+			.iter()
+			.map(|c| (c.addr, c.data, c.time as i64))
+			.collect();
+		v.sort();
+		v
+	}
+
+	#[test]
+	fn test_convert_round_trip() {
+		let file = sample_file();
+
+		// Raw → Standard must extract an instrument and a data template.
+		let standard = file.to_format(OpbFormat::Standard);
+		assert_eq!(standard.format(), OpbFormat::Standard);
+		assert_eq!(standard.instruments.len(), 1);
+		assert_eq!(standard.data_map.len(), 1);
+
+		// Round-trip the Standard form through bytes and back.
+		let bytes = standard.to_bytes();
+		let reparsed = super::parse_opb(&bytes).unwrap().1;
+		assert_eq!(reparsed.format(), OpbFormat::Standard);
+		assert_eq!(stream(&file), stream(&reparsed));
+
+		// And flatten back to Raw, still lossless.
+		let raw = reparsed.to_format(OpbFormat::Raw);
+		let raw_bytes = raw.to_bytes();
+		let raw_reparsed = super::parse_opb(&raw_bytes).unwrap().1;
+		assert_eq!(raw_reparsed.format(), OpbFormat::Raw);
+		assert_eq!(stream(&file), stream(&raw_reparsed));
+	}
+
 	#[test]
-	fn test_read_opb() {
-		let input = include_bytes!("../test_data/test.opb");
-		println!("{:#?}", super::parse_opb(input).unwrap().1);
+	fn test_export_headers() {
+		let file = sample_file();
+		assert_eq!(&file.to_dro().unwrap()[0..8], b"DBRAWOPL");
+		assert_eq!(&file.to_vgm()[0..4], b"Vgm ");
+	}
+
+	#[test]
+	fn test_dro_codemap_overflow() {
+		// A file touching more than 127 distinct low-byte registers cannot be
+		// addressed by the 7-bit DRO codemap index.
+		let cmd_stream: Vec<OpbCommand> = (0..200u16)
+			.map(|i| OpbCommand { addr: i, data: 0, time: i as f64, order_index: i as i32, data_index: -1 })
+			.collect();
+		let file = OpbFile {
+			header: OpbHeader { id: super::FILE_ID, fmt: OpbFormat::Raw, size: 0, num_instruments: 0, num_chunks: cmd_stream.len() as u32 },
+			cmd_stream,
+			instruments: Vec::new(),
+			data_map: Vec::new(),
+		};
+		assert!(matches!(file.to_dro(), Err(super::OpbError::CodemapOverflow { .. })));
+	}
+
+	#[cfg(feature = "render")]
+	#[test]
+	fn test_render_keyed_note_is_audible() {
+		// `sample_file` keys channel 0 on (0xB0 data bit 0x20), so the emulator
+		// must produce at least one non-zero sample.
+		let file = sample_file();
+		let pcm = file.render(super::RenderOptions::default());
+		assert!(pcm.iter().any(|&s| s != 0), "rendered a keyed note but got silence");
+	}
+
+	#[cfg(feature = "disasm")]
+	#[test]
+	fn test_disasm_labels_key_on() {
+		// The 0xB0 write in `sample_file` must be annotated as key-on, not
+		// mislabeled as F-number low.
+		let dump = sample_file().disassemble();
+		assert!(dump.contains("key-on/block/F-number high"), "key-on write was not labeled:\n{dump}");
+	}
+
+	#[test]
+	fn test_truncated_field_offset() {
+		// Valid magic and format byte, then the stream cuts off partway through
+		// the header size field.
+		let input = b"OPBin1\x00\x00\x00\x00";
+		match super::parse_opb(input) {
+			Err(nom::Err::Failure(super::OpbError::TruncatedField { field, offset })) => {
+				assert_eq!(field, "header.size");
+				assert_eq!(offset, 8);
+			}
+			other => panic!("expected TruncatedField, got {other:?}"),
+		}
+	}
+
+	#[test]
+	fn test_u7_round_trip() {
+		for &v in &[127u32, 128, 16383, 16384, 2097151, 2097152] {
+			let mut out = Vec::with_capacity(super::size_u7(v));
+			super::write_u7(&mut out, v);
+			assert_eq!(out.len(), super::size_u7(v));
+			assert_eq!(super::read_u7(&out).unwrap().1, v);
+		}
 	}
 }